@@ -1,4 +1,5 @@
 import asyncio
+import fnmatch
 import json
 import logging
 import os
@@ -24,6 +25,10 @@ VIDEO_TYPES = ['boot', 'suspend', 'throbber']
 VIDEO_TARGETS = ['boot', 'suspend', 'suspend']
 
 REQUEST_RETRIES = 5
+REQUEST_TIMEOUT = 300
+POST_APPLY_HOOK_TIMEOUT = 30
+AUTO_SHUFFLE_INTERVAL = 10
+CONFIG_VERSION = 1
 
 ssl_ctx = ssl.create_default_context(cafile=certifi.where())
 
@@ -33,12 +38,22 @@ local_sets = []
 animation_cache = []
 unloaded = False
 auto_shuffle_task = None
+post_apply_hook_task = None
+applied_animations = None
+
+
+def create_session():
+    """ Create a web session honoring the network settings """
+    return ClientSession(
+        connector=TCPConnector(family=socket.AF_INET) if config['force_ipv4'] else None,
+        timeout=aiohttp.ClientTimeout(total=REQUEST_TIMEOUT, connect=config['connection_timeout'])
+    )
 
 
 async def get_steamdeckrepo():
     try:
         for _ in range(REQUEST_RETRIES):
-            async with ClientSession(connector=TCPConnector(family=socket.AF_INET) if config['force_ipv4'] else None) as web:
+            async with create_session() as web:
                 async with web.request(
                         'get',
                         f'https://steamdeckrepo.com/api/posts/all',
@@ -98,9 +113,24 @@ async def regenerate_downloads():
     config['downloads'] = downloads
 
 
+def migrate_config():
+    """ Upgrade a config loaded from an older plugin version, returning whether anything changed """
+    version = config.get('config_version', 0)
+    if version >= CONFIG_VERSION:
+        return False
+    if version < 1:
+        # Randomize used to be a boolean toggle before set randomization was added
+        if type(config['randomize']) == bool:
+            config['randomize'] = ''
+    config['config_version'] = CONFIG_VERSION
+    decky_plugin.logger.info(f'Migrated config from version {version} to {CONFIG_VERSION}')
+    return True
+
+
 async def load_config():
     global config
     config = {
+        'enabled': True,
         'boot': '',
         'suspend': '',
         'throbber': '',
@@ -109,15 +139,25 @@ async def load_config():
         'downloads': [],
         'custom_animations': [],
         'custom_sets': [],
+        'disabled_sets': [],
         'shuffle_exclusions': [],
+        'shuffle_weights': {},
+        'shuffle_history': {},
+        'anti_repeat_window': 1,
+        'playlists': {'boot': [], 'suspend': [], 'throbber': []},
+        'playlist_positions': {},
         'force_ipv4': False,
+        'connection_timeout': 30,
+        'log_level': 'INFO',
+        'post_apply_hook': '',
         'auto_shuffle_enabled': False,
-        'auto_shuffle_interval': 10
+        'auto_shuffle_interval': AUTO_SHUFFLE_INTERVAL
     }
 
     async def save_new():
         try:
             await regenerate_downloads()
+            config['config_version'] = CONFIG_VERSION
             save_config()
         except Exception as ex:
             decky_plugin.logger.error('Failed to save new config', exc_info=ex)
@@ -126,13 +166,23 @@ async def load_config():
         try:
             with open(CONFIG_PATH) as f:
                 config.update(json.load(f))
-                if type(config['randomize']) == bool:
-                    config['randomize'] = ''
         except Exception as e:
             decky_plugin.logger.error('Failed to load config', exc_info=e)
             await save_new()
+        else:
+            if migrate_config():
+                save_config()
     else:
         await save_new()
+    apply_log_level()
+
+
+def apply_log_level():
+    level = logging.getLevelName(str(config['log_level']).upper())
+    if type(level) != int:
+        decky_plugin.logger.warning(f'Invalid log level: {config["log_level"]}')
+        return
+    decky_plugin.logger.setLevel(level)
 
 
 def raise_and_log(msg, ex=None):
@@ -150,53 +200,69 @@ def save_config():
         raise_and_log('Failed to save config', e)
 
 
+def load_animation_set(directory):
+    """ Parse a set directory in the animations path, returning the set entry and its animations or None if not a set """
+    set_path = f'{ANIMATIONS_PATH}/{directory}'
+    is_set = False
+    config_path = f'{set_path}/config.json'
+    anim_config = {}
+    if os.path.exists(config_path):
+        try:
+            with open(config_path) as f:
+                anim_config = json.load(f)
+            is_set = True
+        except Exception as e:
+            decky_plugin.logger.warning(f'Failed to parse config.json for: {directory}', exc_info=e)
+    else:
+        for video in VIDEOS_NAMES:
+            if os.path.exists(f'{set_path}/{video}'):
+                is_set = True
+                break
+    if not is_set:
+        return None
+
+    local_set = {
+        'id': directory,
+        'enabled': (anim_config['enabled'] if 'enabled' in anim_config else True) and directory not in config['disabled_sets']
+    }
+    animations = []
+
+    for i in range(3):
+        anim_type = VIDEO_TYPES[i]
+        filename = VIDEOS_NAMES[i] if anim_type not in anim_config else anim_config[anim_type]
+        if anim_type not in anim_config and not os.path.exists(f'{set_path}/{filename}'):
+            filename = ''
+        elif filename is not None and filename != '' and (type(filename) != str or not os.path.isfile(f'{set_path}/{filename}')):
+            decky_plugin.logger.warning(f'Invalid {anim_type} animation for set {directory}: {filename}')
+            filename = ''
+        local_set[anim_type] = filename
+        if filename != '' and filename is not None and local_set['enabled']:
+            animations.append({
+                'id': f'{directory}/{filename}',
+                'name': directory if anim_type == 'boot' else f'{directory} - {anim_type.capitalize()}',
+                'target': VIDEO_TARGETS[i]
+            })
+
+    return local_set, animations
+
+
 def load_local_animations():
     global local_animations
     global local_sets
 
     animations = []
     sets = []
+    os.makedirs(ANIMATIONS_PATH, exist_ok=True)
     directories = next(os.walk(ANIMATIONS_PATH))[1]
     for directory in directories:
-        is_set = False
-        config_path = f'{ANIMATIONS_PATH}/{directory}/config.json'
-        anim_config = {}
-        if os.path.exists(config_path):
-            try:
-                with open(config_path) as f:
-                    anim_config = json.load(f)
-                is_set = True
-            except Exception as e:
-                decky_plugin.logger.warning(f'Failed to parse config.json for: {directory}', exc_info=e)
-        else:
-            for video in [BOOT_VIDEO, SUSPEND_VIDEO, THROBBER_VIDEO]:
-                if os.path.exists(f'{ANIMATIONS_PATH}/{directory}/{video}'):
-                    is_set = True
-                    break
-        if not is_set:
+        if (result := load_animation_set(directory)) is None:
             continue
-
-        local_set = {
-            'id': directory,
-            'enabled': anim_config['enabled'] if 'enabled' in anim_config else True
-        }
-
-        def process_animation(default, anim_type, target):
-            filename = default if anim_type not in anim_config else anim_config[anim_type]
-            if anim_type not in anim_config and not os.path.exists(f'{ANIMATIONS_PATH}/{directory}/{filename}'):
-                filename = ''
-            local_set[anim_type] = filename
-            if filename != '' and filename is not None:
-                animations.append({
-                    'id': f'{directory}/{filename}',
-                    'name': directory if anim_type == 'boot' else f'{directory} - {anim_type.capitalize()}',
-                    'target': target
-                })
-
-        for i in range(3):
-            process_animation(VIDEOS_NAMES[i], VIDEO_TYPES[i], VIDEO_TARGETS[i])
-
+        local_set, set_animations = result
         sets.append(local_set)
+        animations += set_animations
+
+    if len(sets) == 0:
+        decky_plugin.logger.info(f'No local animation sets found in: {ANIMATIONS_PATH}')
 
     local_animations = animations
     local_sets = sets
@@ -209,39 +275,106 @@ def find_cached_animation(anim_id):
     return None
 
 
+def remove_override(path):
+    if os.path.islink(path) or os.path.exists(path):
+        os.remove(path)
+
+
+def find_animation_path(anim_id):
+    for anim in config['downloads']:
+        if anim['id'] == anim_id:
+            return f'{DOWNLOADS_PATH}/{anim_id}.webm'
+    for anim in config['custom_animations']:
+        if anim['id'] == anim_id:
+            return anim['path']
+    for anim in local_animations:
+        if anim['id'] == anim_id:
+            return ANIMATIONS_PATH + '/' + anim_id
+    return None
+
+
 def apply_animation(video, anim_id):
     override_path = f'{OVERRIDE_PATH}/{video}'
-    if os.path.islink(override_path) or os.path.exists(override_path):
-        os.remove(override_path)
 
     if anim_id == '':
+        remove_override(override_path)
         return
 
-    path = None
-    for anim in config['downloads']:
-        if anim['id'] == anim_id:
-            path = f'{DOWNLOADS_PATH}/{anim_id}.webm'
-            break
-    else:
-        for anim in config['custom_animations']:
-            if anim['id'] == anim_id:
-                path = anim['path']
-                break
-        else:
-            for anim in local_animations:
-                if anim['id'] == anim_id:
-                    path = ANIMATIONS_PATH + '/' + anim_id
-                    break
-
+    path = find_animation_path(anim_id)
     if path is None or not os.path.exists(path):
+        remove_override(override_path)
         raise_and_log(f'Failed to find animation for: {anim_id}')
 
-    os.symlink(path, override_path)
+    # Swap the new link in with a rename so Steam never sees a missing override
+    temp_path = f'{override_path}.tmp'
+    remove_override(temp_path)
+    os.symlink(path, temp_path)
+    os.replace(temp_path, override_path)
 
 
 def apply_animations():
+    global applied_animations
+    if not config['enabled']:
+        decky_plugin.logger.info('Animation management is disabled, leaving overrides untouched')
+        return
+    applied = {}
     for i in range(3):
-        apply_animation(VIDEOS_NAMES[i], config[VIDEO_TYPES[i]])
+        anim_id = config[VIDEO_TYPES[i]]
+        if anim_id != '' and ((path := find_animation_path(anim_id)) is None or not os.path.exists(path)):
+            # The file may be on unmounted storage or in a disabled set, so keep the selection and use stock for now
+            decky_plugin.logger.warning(f'Animation unavailable, using default {VIDEO_TYPES[i]} animation: {anim_id}')
+            anim_id = ''
+        apply_animation(VIDEOS_NAMES[i], anim_id)
+        applied[VIDEO_TYPES[i]] = anim_id
+    if applied != applied_animations:
+        applied_animations = applied
+        start_post_apply_hook(applied)
+
+
+async def run_post_apply_hook(command, env):
+    """ Run the user's post-apply hook command, only warning if it fails """
+    process = None
+    try:
+        process = await asyncio.create_subprocess_shell(command, env=env)
+        try:
+            await asyncio.wait_for(process.wait(), POST_APPLY_HOOK_TIMEOUT)
+        except asyncio.TimeoutError:
+            process.kill()
+            await process.wait()
+            decky_plugin.logger.warning(f'Post-apply hook timed out after {POST_APPLY_HOOK_TIMEOUT} seconds')
+            return
+        if process.returncode != 0:
+            decky_plugin.logger.warning(f'Post-apply hook exited with status: {process.returncode}')
+    except asyncio.CancelledError:
+        # Superseded by a newer apply, so don't leave the outdated hook running
+        if process is not None and process.returncode is None:
+            process.kill()
+            await process.wait()
+        raise
+    except Exception as e:
+        decky_plugin.logger.warning('Failed to run post-apply hook', exc_info=e)
+
+
+def start_post_apply_hook(applied):
+    """ Start the post-apply hook in the background with the applied animation ids in its environment """
+    global post_apply_hook_task
+    if config['post_apply_hook'] == '':
+        return
+    if post_apply_hook_task is not None and not post_apply_hook_task.done():
+        post_apply_hook_task.cancel()
+    env = dict(os.environ)
+    for anim_type in VIDEO_TYPES:
+        env[f'ANIMATION_{anim_type.upper()}'] = applied[anim_type]
+    post_apply_hook_task = asyncio.create_task(run_post_apply_hook(config['post_apply_hook'], env))
+
+
+def stop_post_apply_hook():
+    """ Cancel a running post-apply hook, killing its process """
+    global post_apply_hook_task
+    if post_apply_hook_task and not post_apply_hook_task.done():
+        post_apply_hook_task.cancel()
+        post_apply_hook_task = None
+        decky_plugin.logger.info('Post-apply hook cancelled')
 
 
 def get_active_sets():
@@ -256,24 +389,106 @@ def remove_custom_animation(anim_id):
     config['custom_animations'] = [anim for anim in config['custom_animations'] if anim['id'] != anim_id]
 
 
+def matches_exclusion(pattern, entry_id):
+    if pattern.startswith('glob:'):
+        return fnmatch.fnmatchcase(entry_id, pattern[len('glob:'):])
+    return pattern == entry_id
+
+
+def is_type_excluded(anim_type):
+    return f'type:{anim_type}' in config['shuffle_exclusions']
+
+
+def is_shuffle_excluded(anim_id, anim_type):
+    """ Check an animation against the shuffle exclusions, which are exact ids, 'glob:MySet/*' patterns, or 'type:boot' """
+    if is_type_excluded(anim_type):
+        return True
+    return any(matches_exclusion(pattern, anim_id) for pattern in config['shuffle_exclusions'])
+
+
+def is_set_shuffle_excluded(set_id):
+    """ Check a set against the shuffle exclusions, where a pattern covering the set directory like 'glob:MySet/*' excludes it """
+    return any(
+        matches_exclusion(pattern, set_id) or matches_exclusion(pattern, f'{set_id}/')
+        for pattern in config['shuffle_exclusions']
+    )
+
+
+def get_set_animation(set_entry, anim_type):
+    """ Resolve a set's animation entry to an animation id, or None to leave the existing animation """
+    anim = set_entry.get(anim_type, '')
+    if anim is None or anim == '':
+        return anim
+    # Local sets reference files relative to the set directory, custom sets reference animation ids
+    if set_entry in local_sets:
+        return f'{set_entry["id"]}/{anim}'
+    return anim
+
+
 def randomize_current_set():
-    active = get_active_sets()
+    active = [entry for entry in get_active_sets() if not is_set_shuffle_excluded(entry['id'])]
     new_set = {'boot': '', 'suspend': '', 'throbber': ''}
     if len(active) > 0:
         new_set = active[random.randint(0, len(active) - 1)]
         config['current_set'] = new_set['id']
     for i in range(3):
-        config[VIDEO_TYPES[i]] = new_set[VIDEO_TYPES[i]]['id']
+        if is_type_excluded(VIDEO_TYPES[i]):
+            continue
+        anim_id = get_set_animation(new_set, VIDEO_TYPES[i])
+        if anim_id is not None:
+            config[VIDEO_TYPES[i]] = anim_id
+
+
+def get_shuffle_weight(anim_id):
+    # Animations without an explicit weight default to 1, and a weight of 0 never gets picked
+    weight = config['shuffle_weights'].get(anim_id, 1)
+    if type(weight) not in [int, float]:
+        decky_plugin.logger.warning(f'Invalid shuffle weight for {anim_id}: {weight}')
+        return 1
+    return max(weight, 0)
 
 
 def randomize_all():
+    window = config['anti_repeat_window']
+    if type(window) != int:
+        decky_plugin.logger.warning(f'Invalid anti-repeat window: {window}')
+        window = 1
+    window = max(window, 0)
     for i in range(3):
+        anim_type = VIDEO_TYPES[i]
         pool = [
             anim for anim in local_animations + config['downloads'] + config['custom_animations']
-            if anim['target'] == VIDEO_TARGETS[i] and anim['id'] not in config['shuffle_exclusions']
+            if anim['target'] == VIDEO_TARGETS[i] and not is_shuffle_excluded(anim['id'], anim_type)
+            and get_shuffle_weight(anim['id']) > 0
         ]
-        if len(pool) > 0:
-            config[VIDEO_TYPES[i]] = pool[random.randint(0, len(pool) - 1)]['id']
+        if len(pool) == 0:
+            continue
+        history = config['shuffle_history'].get(anim_type, [])
+        if len(history) == 0 or history[-1] != config[anim_type]:
+            history = history + [config[anim_type]]
+        # Avoid recently shown animations, shrinking the window until there is an alternative
+        for size in range(window, 0, -1):
+            fresh = [anim for anim in pool if anim['id'] not in history[-size:]]
+            if len(fresh) > 0:
+                pool = fresh
+                break
+        config[anim_type] = random.choices(pool, [get_shuffle_weight(anim['id']) for anim in pool])[0]['id']
+        config['shuffle_history'][anim_type] = (history + [config[anim_type]])[-window:] if window > 0 else []
+    config['current_set'] = ''
+
+
+def advance_playlists():
+    for anim_type in VIDEO_TYPES:
+        playlist = config['playlists'].get(anim_type, [])
+        if len(playlist) == 0:
+            continue
+        # The position is the index of the last shown entry, so the first advance starts at the beginning
+        position = config['playlist_positions'].get(anim_type, -1)
+        if type(position) != int:
+            position = -1
+        position = (position + 1) % len(playlist)
+        config['playlist_positions'][anim_type] = position
+        config[anim_type] = playlist[position]
     config['current_set'] = ''
 
 
@@ -282,9 +497,9 @@ async def auto_shuffle_daemon():
     global unloaded
     while not unloaded:
         try:
-            interval = config.get('auto_shuffle_interval', 120)  # Default to 2 minutes
+            interval = config.get('auto_shuffle_interval', AUTO_SHUFFLE_INTERVAL)
             await asyncio.sleep(interval)
-            if unloaded or not config.get('auto_shuffle_enabled', False):
+            if unloaded or not config.get('auto_shuffle_enabled', False) or not config['enabled']:
                 continue
             
             decky_plugin.logger.info('Auto-shuffle: Shuffling animations')
@@ -317,6 +532,12 @@ def stop_auto_shuffle_daemon():
         decky_plugin.logger.info('Auto-shuffle daemon stopped')
 
 
+def restart_auto_shuffle_daemon():
+    """Restart the auto shuffle daemon so a changed interval takes effect immediately"""
+    stop_auto_shuffle_daemon()
+    start_auto_shuffle_daemon()
+
+
 class Plugin:
 
     async def getState(self):
@@ -329,6 +550,7 @@ class Plugin:
                 'local_sets': local_sets,
                 'custom_sets': config['custom_sets'],
                 'settings': {
+                    'enabled': config['enabled'],
                     'randomize': config['randomize'],
                     'current_set': config['current_set'],
                     'boot': config['boot'],
@@ -368,13 +590,17 @@ class Plugin:
         try:
             for entry in local_sets:
                 if entry['id'] == set_id:
-                    entry['enable'] = enable
-                    with open(f'{ANIMATIONS_PATH}/{entry["name"]}/config.json', 'w') as f:
-                        json.dump(entry, f)
+                    config['disabled_sets'] = [entry_id for entry_id in config['disabled_sets'] if entry_id != set_id]
+                    if not enable:
+                        config['disabled_sets'].append(set_id)
+                    save_config()
+                    load_local_animations()
+                    if enable and not any(entry['enabled'] for entry in local_sets if entry['id'] == set_id):
+                        decky_plugin.logger.warning(f'Set {set_id} is still disabled by its own config.json')
                     return
             for entry in config['custom_sets']:
                 if entry['id'] == set_id:
-                    entry['enable'] = enable
+                    entry['enabled'] = enable
                     save_config()
                     break
         except Exception as e:
@@ -430,7 +656,7 @@ class Plugin:
             for entry in config['downloads']:
                 if entry['id'] == anim_id:
                     return
-            async with aiohttp.ClientSession(connector=TCPConnector(family=socket.AF_INET) if config['force_ipv4'] else None) as web:
+            async with create_session() as web:
                 if (anim := find_cached_animation(anim_id)) is None:
                     raise_and_log(f'Failed to find cached animation with id: {id}')
                 async with web.get(anim['download_url'], ssl=ssl_ctx) as response:
@@ -458,8 +684,9 @@ class Plugin:
     async def saveSettings(self, settings):
         """ Save settings to config file """
         try:
-            # Check if auto_shuffle_enabled changed
+            # Check if auto_shuffle_enabled or auto_shuffle_interval changed
             old_auto_shuffle = config.get('auto_shuffle_enabled', False)
+            old_interval = config.get('auto_shuffle_interval')
             config.update(settings)
             new_auto_shuffle = config.get('auto_shuffle_enabled', False)
             
@@ -472,6 +699,8 @@ class Plugin:
                     start_auto_shuffle_daemon()
                 else:
                     stop_auto_shuffle_daemon()
+            elif new_auto_shuffle and old_interval != config.get('auto_shuffle_interval'):
+                restart_auto_shuffle_daemon()
                     
         except Exception as e:
             decky_plugin.logger.error('Failed to save settings', exc_info=e)
@@ -483,6 +712,7 @@ class Plugin:
             await load_config()
             load_local_animations()
             apply_animations()
+            restart_auto_shuffle_daemon()
         except Exception as e:
             decky_plugin.logger.error('Failed to reload configuration', exc_info=e)
             raise e
@@ -490,6 +720,9 @@ class Plugin:
     async def randomize(self, shuffle):
         """ Randomize animations """
         try:
+            if not config['enabled']:
+                decky_plugin.logger.info('Animation management is disabled, not randomizing')
+                return
             if shuffle:
                 randomize_all()
             else:
@@ -520,10 +753,15 @@ class Plugin:
             raise e
 
         try:
-            if config['randomize'] == 'all':
-                randomize_all()
-            elif config['randomize'] == 'set':
-                randomize_current_set()
+            # Leave selections, shuffle history and playlist positions alone while management is disabled
+            if config['enabled']:
+                if config['randomize'] == 'all':
+                    randomize_all()
+                elif config['randomize'] == 'set':
+                    randomize_current_set()
+                elif config['randomize'] == 'sequential':
+                    advance_playlists()
+                save_config()
         except Exception as e:
             decky_plugin.logger.error('Failed to randomize animations', exc_info=e)
             raise e
@@ -552,6 +790,7 @@ class Plugin:
         global unloaded
         unloaded = True
         stop_auto_shuffle_daemon()
+        stop_post_apply_hook()
         decky_plugin.logger.info('Unloaded')
 
     async def _migration(self):
@@ -91,9 +91,7 @@ export const AnimationBrowserPage: FC = () => {
 
     // Filter the results based on the query
     if(query && query.length > 0) {
-      filtered = filtered.filter((result) => {
-        return result.name.toLowerCase().includes(query.toLowerCase());
-      });
+      filtered = filtered.filter((result) => result.matches(query));
     }
 
     // Sort based on the dropdown
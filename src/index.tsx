@@ -37,7 +37,7 @@ const AutoShuffleToggle: FC<{settings: any, saveSettings: any, loadBackendState:
     
     const currentInterval = settings.auto_shuffle_interval || 10; // Default to 10 seconds
     const currentIndex = intervalOptions.indexOf(currentInterval);
-    const currentLabel = intervalLabels[currentIndex] || '2 minutes';
+    const currentLabel = intervalLabels[currentIndex] || `${currentInterval} seconds`;
 
     useEffect(() => {
         if (settings.auto_shuffle_enabled) {
@@ -219,6 +219,15 @@ const Content: FC = () => {
 
             </PanelSection>
             <PanelSection title='Settings'>
+                <PanelSectionRow>
+                    <ToggleField
+                    label='Enable Animation Changer'
+                    description='When disabled, Steam animation files are left untouched'
+                    onChange={(checked) => { saveSettings({ ...settings, enabled: checked }) }}
+                    checked={settings.enabled}
+                    />
+                </PanelSectionRow>
+
                 <PanelSectionRow>
                     <ToggleField
                     label='Shuffle on Boot'
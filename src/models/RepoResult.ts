@@ -37,6 +37,12 @@ export default class RepoResult implements IRepoResult {
     return this.moment_date.fromNow();
   }
 
+  matches(query: string): boolean {
+    const lowerQuery = query.toLowerCase();
+    return [this.name, this.id, this.author]
+      .some((field) => (field || '').toLowerCase().includes(lowerQuery));
+  }
+
   constructor(json: any) {
     Object.assign(this, json);
     this.moment_date = moment(this.last_changed);
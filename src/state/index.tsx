@@ -34,6 +34,7 @@ export const AnimationProvider: FC<AnimationProviderType> = ({ serverAPI, childr
   
 
   const [ settings, setSettings ] = useState<PluginSettings>({
+    enabled: true,
     randomize: '',
     current_set: '',
     boot: '',
@@ -15,9 +15,11 @@ export interface IRepoResult extends Animation  {
   manifest_version: number;
   moment_date: Moment;
   readonly relative_date: string;
+  matches: (query: string) => boolean;
 }
 
 export interface PluginSettings {
+  enabled: boolean;
   randomize: String;
   current_set: String;
   boot: String;